@@ -3,6 +3,7 @@
 use clap::{Parser, Subcommand};
 
 pub use color::Color;
+pub use text::ColorGlyphMode;
 
 /// Manipulate background images.
 #[derive(Debug, Subcommand)]
@@ -59,36 +60,365 @@ mod color {
 
 mod text {
     //! Producing an image of a text.
+    use std::collections::HashMap;
     use std::error::Error;
+    use std::num::NonZeroUsize;
 
-    use font_kit::canvas::{Canvas, Format};
+    use font_kit::canvas::{Canvas, Format, RasterizationOptions};
+    use font_kit::family_name::FamilyName;
     use font_kit::font::Font;
+    use font_kit::hinting::HintingOptions;
+    use font_kit::properties::Properties;
+    use font_kit::source::SystemSource;
+    use image::{Rgba, RgbaImage};
+    use lru::LruCache;
     use pathfinder_geometry::rect::RectI;
-    use pathfinder_geometry::vector::vec2i;
+    use pathfinder_geometry::transform2d::Transform2F;
+    use pathfinder_geometry::vector::{vec2f, vec2i};
+    use unicode_bidi::BidiInfo;
+    use unicode_segmentation::UnicodeSegmentation;
 
+    /// Installed font families tried, in order, when none of the caller's fonts cover a
+    /// character. Broad enough to catch the common Latin/CJK/Arabic/Hebrew/emoji gaps.
+    const FALLBACK_CASCADE: &[&str] = &[
+        "Noto Sans",
+        "Noto Sans Arabic",
+        "Noto Sans Hebrew",
+        "Noto Sans CJK SC",
+        "Noto Color Emoji",
+        "Arial Unicode MS",
+        "DejaVu Sans",
+    ];
 
-    /// Draw provided string of text with a random font and a random size.
-    pub fn draw(s: &str, min_point: u32, max_size: (u32, u32)) -> Result<(), TextError> {
-        let font = pick_random_font()?;
-        font.load_font_table(table_tag)
-        Ok(())
+    /// Caches the system font resolved for a codepoint range so repeated fallback lookups for
+    /// the same script don't re-walk [`FALLBACK_CASCADE`].
+    #[derive(Default)]
+    pub struct FallbackCache {
+        by_range: HashMap<(u32, u32), Font>,
     }
 
-    pub fn draw_with_font(s: &str, font: &Font) -> Result<(), TextError> {
-        let (w, h) = text_dimensions(s, font)?;
-        let mut canvas = Canvas::new(vec2i(w, h), Format::Rgba32);
-        for ch in s.chars() {
-            let glyph = font.glyph_for_char(ch).ok_or(TextError::MissingGlyphError(ch))?;
+    impl FallbackCache {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Coarse 256-codepoint block `ch` falls into; close enough to a Unicode block for
+        /// caching purposes without needing a block-boundary table.
+        fn range_for(ch: char) -> (u32, u32) {
+            let block = (ch as u32) & !0xFF;
+            (block, block + 0xFF)
+        }
+
+        fn resolve(&mut self, ch: char) -> Result<Font, TextError> {
+            let range = Self::range_for(ch);
+            if let Some(font) = self.by_range.get(&range) {
+                if font.glyph_for_char(ch).is_some() {
+                    return Ok(font.clone());
+                }
+            }
+            let font = cascade_font_for_char(ch)?;
+            self.by_range.insert(range, font.clone());
+            Ok(font)
+        }
+    }
+
+    /// Default capacity of a [`GlyphCache`]: generous enough to hold every glyph of a few long
+    /// repeated strings without thrashing.
+    const GLYPH_CACHE_CAPACITY: usize = 1000;
+
+    /// Identifies one rasterized glyph: a specific font, glyph id and point size.
+    #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+    struct GlyphKey {
+        font_id: String,
+        glyph_id: u32,
+        point_size_bits: u32,
+    }
+
+    impl GlyphKey {
+        fn new(font: &Font, glyph_id: u32, point_size: f32) -> Self {
+            Self {
+                font_id: font.full_name(),
+                glyph_id,
+                point_size_bits: point_size.to_bits(),
+            }
+        }
+    }
+
+    /// A glyph's rasterized coverage buffer, plus the bounds and advance needed to place it.
+    struct CachedGlyph {
+        pixels: Vec<u8>,
+        stride: usize,
+        size: pathfinder_geometry::vector::Vector2I,
+        /// Offset from the pen position to the buffer's top-left corner.
+        origin: (i32, i32),
+        advance: i32,
+    }
+
+    /// Bounded LRU cache of rasterized glyphs, keyed on `(font, glyph, point size)`, so redrawing
+    /// the same text repeatedly (e.g. for animated or incremental updates) doesn't re-rasterize
+    /// every glyph on every call.
+    pub struct GlyphCache {
+        entries: LruCache<GlyphKey, CachedGlyph>,
+        misses: usize,
+    }
+
+    impl GlyphCache {
+        pub fn new() -> Self {
+            Self {
+                entries: LruCache::new(NonZeroUsize::new(GLYPH_CACHE_CAPACITY).unwrap()),
+                misses: 0,
+            }
+        }
+
+        /// Number of glyphs actually rasterized so far (cache misses). Exposed for tests and
+        /// instrumentation, not for cache control.
+        pub fn misses(&self) -> usize {
+            self.misses
+        }
+    }
+
+    impl Default for GlyphCache {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    /// Rasterize `glyph` at `point_size` using `font`, consulting `cache` first and inserting on
+    /// miss, evicting the least-recently-used entry once the cache is full.
+    fn rasterize_cached<'c>(
+        cache: &'c mut GlyphCache,
+        font: &Font,
+        glyph: u32,
+        point_size: f32,
+    ) -> Result<&'c CachedGlyph, TextError> {
+        let key = GlyphKey::new(font, glyph, point_size);
+        if cache.entries.get(&key).is_none() {
+            let bounds = font.typographic_bounds(glyph)?.scale(point_size).to_i32();
+            let advance = glyph_advance(font, glyph, point_size)?;
+            let size = vec2i(bounds.width().max(1), bounds.height().max(1));
+            let mut glyph_canvas = Canvas::new(size, Format::Rgba32);
+            let transform =
+                Transform2F::from_translation(vec2f(-bounds.min_x() as f32, -bounds.min_y() as f32));
             font.rasterize_glyph(
-                &mut canvas,
+                &mut glyph_canvas,
                 glyph,
                 point_size,
                 transform,
-                hinting_options,
-                rasterization_options,
+                HintingOptions::None,
+                RasterizationOptions::GrayscaleAa,
             )?;
+            cache.misses += 1;
+            cache.entries.put(
+                key.clone(),
+                CachedGlyph {
+                    pixels: glyph_canvas.pixels,
+                    stride: glyph_canvas.stride,
+                    size,
+                    origin: (bounds.min_x(), bounds.min_y()),
+                    advance,
+                },
+            );
         }
-        Ok(())
+        Ok(cache.entries.get(&key).expect("just verified present"))
+    }
+
+    /// Copy a cached glyph's coverage buffer onto `canvas` with its pen position at `cursor`.
+    fn blit_cached_glyph(canvas: &mut Canvas, cached: &CachedGlyph, cursor: i32) {
+        let x0 = cursor + cached.origin.0;
+        let y0 = cached.origin.1;
+        for y in 0..cached.size.y() {
+            let dst_y = y0 + y;
+            if dst_y < 0 || dst_y >= canvas.size.y() {
+                continue;
+            }
+            let src_row = y as usize * cached.stride;
+            let dst_row = dst_y as usize * canvas.stride;
+            for x in 0..cached.size.x() {
+                let dst_x = x0 + x;
+                if dst_x < 0 || dst_x >= canvas.size.x() {
+                    continue;
+                }
+                let src_offset = src_row + x as usize * 4;
+                let dst_offset = dst_row + dst_x as usize * 4;
+                canvas.pixels[dst_offset..dst_offset + 4]
+                    .copy_from_slice(&cached.pixels[src_offset..src_offset + 4]);
+            }
+        }
+    }
+
+    /// Walk [`FALLBACK_CASCADE`] for the first installed font with a glyph for `ch`, then, if
+    /// none of those families are installed or cover it, scan every installed font the way
+    /// [`pick_random_font`] does. This only fails when truly no installed font covers `ch`.
+    fn cascade_font_for_char(ch: char) -> Result<Font, TextError> {
+        let source = SystemSource::new();
+        for family in FALLBACK_CASCADE {
+            let handle =
+                match source.select_best_match(&[FamilyName::Title((*family).to_string())], &Properties::new()) {
+                    Ok(handle) => handle,
+                    Err(_) => continue,
+                };
+            if let Ok(font) = Font::from_handle(&handle) {
+                if font.glyph_for_char(ch).is_some() {
+                    return Ok(font);
+                }
+            }
+        }
+        for handle in source.all_fonts()? {
+            if let Ok(font) = Font::from_handle(&handle) {
+                if font.glyph_for_char(ch).is_some() {
+                    return Ok(font);
+                }
+            }
+        }
+        Err(TextError::MissingGlyphError(ch))
+    }
+
+    /// Find the first of `fonts` covering `ch`, falling back to the system cascade and caching
+    /// the result in `fallback` when none do.
+    fn resolve_char(fonts: &[Font], ch: char, fallback: &mut FallbackCache) -> Result<(Font, u32), TextError> {
+        for font in fonts {
+            if let Some(glyph) = font.glyph_for_char(ch) {
+                return Ok((font.clone(), glyph));
+            }
+        }
+        let font = fallback.resolve(ch)?;
+        let glyph = font.glyph_for_char(ch).ok_or(TextError::MissingGlyphError(ch))?;
+        Ok((font, glyph))
+    }
+
+    /// `font.advance` is in the same em-relative space as `typographic_bounds`, so it needs the
+    /// same `point_size` scaling before it can be used as a pixel advance for the pen.
+    fn glyph_advance(font: &Font, glyph: u32, point_size: f32) -> Result<i32, TextError> {
+        Ok((font.advance(glyph)?.x() * point_size) as i32)
+    }
+
+    /// Segment `s` into grapheme clusters and resolve bidirectional runs so the result is in
+    /// visual (left-to-right pen) order: RTL runs have their clusters reversed so advancing the
+    /// pen forward through the returned sequence draws the string correctly.
+    fn layout(s: &str) -> Vec<&str> {
+        let bidi_info = BidiInfo::new(s, None);
+        let mut clusters = Vec::new();
+        for para in &bidi_info.paragraphs {
+            let (levels, runs) = bidi_info.visual_runs(para, para.range.clone());
+            for run in runs {
+                let mut run_clusters: Vec<&str> = s[run.clone()].graphemes(true).collect();
+                if levels[run.start].is_rtl() {
+                    run_clusters.reverse();
+                }
+                clusters.extend(run_clusters);
+            }
+        }
+        clusters
+    }
+
+    /// How color glyph tables (`sbix`, `CBDT`/`CBLC`, `COLR`/`CPAL`) are handled while drawing.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+    pub enum ColorGlyphMode {
+        /// Render color glyphs when the font provides them, falling back to monochrome coverage.
+        Auto,
+        /// Always rasterize through the monochrome coverage path, ignoring color tables.
+        Never,
+    }
+
+    /// A rasterized piece of text plus a per-pixel record of which pixels came from a color
+    /// glyph table (already final color) rather than monochrome coverage that still needs
+    /// tinting with a foreground color. Consumers (like [`super::compositor`]) use this instead
+    /// of guessing from pixel values, since a gray or black color-glyph pixel (a common emoji
+    /// outline color) is indistinguishable from monochrome coverage by value alone.
+    pub struct RasterizedText {
+        pub canvas: Canvas,
+        color_mask: Vec<bool>,
+    }
+
+    impl RasterizedText {
+        /// Whether the pixel at `(x, y)` was written by a color glyph. Out-of-bounds pixels are
+        /// reported as not colored.
+        pub fn is_color_pixel(&self, x: i32, y: i32) -> bool {
+            if x < 0 || y < 0 || x >= self.canvas.size.x() || y >= self.canvas.size.y() {
+                return false;
+            }
+            self.color_mask[y as usize * self.canvas.size.x() as usize + x as usize]
+        }
+    }
+
+    /// Draw `s` with a random installed font, sized as large as possible without its canvas
+    /// exceeding `max_size`, but never smaller than `min_point`.
+    pub fn draw(
+        s: &str,
+        min_point: u32,
+        max_size: (u32, u32),
+        color_glyphs: ColorGlyphMode,
+    ) -> Result<RasterizedText, TextError> {
+        let font = pick_random_font()?;
+        let mut fallback = FallbackCache::new();
+        let mut glyph_cache = GlyphCache::new();
+
+        // Measure at a unit point size, then scale that up to the largest size that still fits
+        // max_size, so we don't have to re-measure repeatedly.
+        let (unit_w, unit_h) = text_dimensions(s, &[font.clone()], 1.0, &mut fallback)?;
+        let fitting_point_size = if unit_w > 0 && unit_h > 0 {
+            (max_size.0 as f32 / unit_w as f32).min(max_size.1 as f32 / unit_h as f32)
+        } else {
+            min_point as f32
+        };
+        let point_size = fitting_point_size.max(min_point as f32).max(1.0);
+
+        draw_with_font(s, &[font], point_size, color_glyphs, &mut fallback, &mut glyph_cache)
+    }
+
+    /// Draw `s` onto a freshly allocated canvas, compositing color glyph tables (`sbix`,
+    /// `CBDT`/`CBLC`, `COLR`/`CPAL`) directly instead of treating their output as an
+    /// alpha-coverage mask. `fonts` is tried in order for each character before falling back to
+    /// the system cascade cached in `fallback`; monochrome glyphs are rasterized through
+    /// `glyph_cache` so repeated strings don't redo the same work.
+    pub fn draw_with_font(
+        s: &str,
+        fonts: &[Font],
+        point_size: f32,
+        color_glyphs: ColorGlyphMode,
+        fallback: &mut FallbackCache,
+        glyph_cache: &mut GlyphCache,
+    ) -> Result<RasterizedText, TextError> {
+        let (w, h) = text_dimensions(s, fonts, point_size, fallback)?;
+        let mut canvas = Canvas::new(vec2i(w, h), Format::Rgba32);
+        let mut color_mask = vec![false; w.max(0) as usize * h.max(0) as usize];
+
+        let mut cursor = 0i32;
+        for cluster in layout(s) {
+            let mut cluster_advance = 0i32;
+            for (i, ch) in cluster.chars().enumerate() {
+                let (font, glyph) = resolve_char(fonts, ch, fallback)?;
+                let transform = Transform2F::from_translation(vec2f(cursor as f32, 0.0));
+
+                let color_table = match color_glyphs {
+                    ColorGlyphMode::Auto => color_table(&font),
+                    ColorGlyphMode::Never => None,
+                };
+                let advance = match color_table {
+                    Some(ColorTable::Sbix) | Some(ColorTable::Cbdt) => {
+                        draw_bitmap_glyph(&mut canvas, &mut color_mask, &font, glyph, point_size, transform)?;
+                        glyph_advance(&font, glyph, point_size)?
+                    }
+                    Some(ColorTable::Colr) => {
+                        draw_colr_glyph(&mut canvas, &mut color_mask, &font, glyph, point_size, transform)?;
+                        glyph_advance(&font, glyph, point_size)?
+                    }
+                    None => {
+                        let cached = rasterize_cached(glyph_cache, &font, glyph, point_size)?;
+                        blit_cached_glyph(&mut canvas, cached, cursor);
+                        cached.advance
+                    }
+                };
+
+                // Only the cluster's base character (the first one) advances the pen;
+                // combining marks stack on top of it.
+                if i == 0 {
+                    cluster_advance = advance;
+                }
+            }
+            cursor += cluster_advance;
+        }
+        Ok(RasterizedText { canvas, color_mask })
     }
 
     pub fn pick_random_font() -> Result<Font, TextError> {
@@ -98,27 +428,334 @@ mod text {
         Ok(font)
     }
 
+    /// Color glyph table family a font exposes, in the order we prefer to use them.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    enum ColorTable {
+        /// Apple-style scalable bitmap glyphs (`sbix`).
+        Sbix,
+        /// Bitmap glyphs addressed through `CBLC` and stored in `CBDT`.
+        Cbdt,
+        /// Layered, palette-tinted vector glyphs (`COLR`/`CPAL`).
+        Colr,
+    }
+
+    /// Build a big-endian OpenType table tag from its four-character name.
+    const fn tag(name: &[u8; 4]) -> u32 {
+        u32::from_be_bytes(*name)
+    }
+
+    /// Detect which color glyph table family, if any, `font` provides.
+    fn color_table(font: &Font) -> Option<ColorTable> {
+        if font.load_font_table(tag(b"sbix")).is_some() {
+            Some(ColorTable::Sbix)
+        } else if font.load_font_table(tag(b"CBDT")).is_some()
+            && font.load_font_table(tag(b"CBLC")).is_some()
+        {
+            Some(ColorTable::Cbdt)
+        } else if font.load_font_table(tag(b"COLR")).is_some()
+            && font.load_font_table(tag(b"CPAL")).is_some()
+        {
+            Some(ColorTable::Colr)
+        } else {
+            None
+        }
+    }
+
+    /// Composite a `sbix` or `CBDT`/`CBLC` bitmap glyph onto `canvas`, copying its RGBA pixels
+    /// instead of rasterizing coverage. A glyph with no decodable bitmap is rasterized as plain
+    /// monochrome coverage instead.
+    fn draw_bitmap_glyph(
+        canvas: &mut Canvas,
+        color_mask: &mut [bool],
+        font: &Font,
+        glyph: u32,
+        point_size: f32,
+        transform: Transform2F,
+    ) -> Result<(), TextError> {
+        let image = match bitmap_glyph_image(font, glyph, point_size)? {
+            Some(image) => image,
+            None => {
+                // Left out of color_mask, so it gets tinted with the caller's foreground color.
+                font.rasterize_glyph(
+                    canvas,
+                    glyph,
+                    point_size,
+                    transform,
+                    HintingOptions::None,
+                    RasterizationOptions::GrayscaleAa,
+                )?;
+                return Ok(());
+            }
+        };
+        let origin = transform.translation().to_i32();
+        composite_rgba_image(canvas, color_mask, &image, origin.x(), origin.y());
+        Ok(())
+    }
+
+    /// Decode the strike closest to `point_size` for `glyph` out of `sbix` or `CBDT`/`CBLC`,
+    /// returning its straight-alpha (non-premultiplied) RGBA pixels as decoded from the PNG.
+    fn bitmap_glyph_image(
+        font: &Font,
+        glyph: u32,
+        point_size: f32,
+    ) -> Result<Option<RgbaImage>, TextError> {
+        if let Some(sbix) = font.load_font_table(tag(b"sbix")) {
+            return Ok(sbix_glyph_image(&sbix, glyph, point_size));
+        }
+        if let (Some(cbdt), Some(cblc)) = (
+            font.load_font_table(tag(b"CBDT")),
+            font.load_font_table(tag(b"CBLC")),
+        ) {
+            return Ok(cbdt_glyph_image(&cbdt, &cblc, glyph, point_size));
+        }
+        Ok(None)
+    }
+
+    fn sbix_glyph_image(sbix: &[u8], glyph: u32, point_size: f32) -> Option<RgbaImage> {
+        let num_strikes = be_u32(sbix, 4)?;
+        let mut best_offset = None;
+        let mut best_diff = u16::MAX;
+        for i in 0..num_strikes {
+            let strike_offset = be_u32(sbix, 8 + i as usize * 4)? as usize;
+            let ppem = be_u16(sbix, strike_offset)?;
+            let diff = (ppem as i32 - point_size as i32).unsigned_abs() as u16;
+            if diff < best_diff {
+                best_diff = diff;
+                best_offset = Some(strike_offset);
+            }
+        }
+        let strike_offset = best_offset?;
+        let data_offset = be_u32(sbix, strike_offset + 4 + glyph as usize * 4)? as usize;
+        let next_offset = be_u32(sbix, strike_offset + 4 + (glyph as usize + 1) * 4)? as usize;
+        if next_offset <= data_offset {
+            return None;
+        }
+        let record = sbix.get(strike_offset + data_offset..strike_offset + next_offset)?;
+        let graphic_type = record.get(4..8)?;
+        let png_data = record.get(8..)?;
+        if graphic_type == b"png " {
+            image::load_from_memory(png_data).ok().map(|i| i.to_rgba8())
+        } else {
+            None
+        }
+    }
+
+    fn cbdt_glyph_image(cbdt: &[u8], cblc: &[u8], glyph: u32, point_size: f32) -> Option<RgbaImage> {
+        let num_sizes = be_u32(cblc, 4)?;
+        let mut best_table = None;
+        let mut best_diff = u8::MAX;
+        for i in 0..num_sizes {
+            let table_offset = 8 + i as usize * 48;
+            let ppem = *cblc.get(table_offset + 45)?;
+            let diff = (ppem as i16 - point_size as i16).unsigned_abs() as u8;
+            if diff < best_diff {
+                best_diff = diff;
+                best_table = Some(table_offset);
+            }
+        }
+        let size_table = best_table?;
+        // `indexTablesSize` is at +4; `numberOfIndexSubTables` is the next field, at +8.
+        let num_subtables = be_u32(cblc, size_table + 8)?;
+        let subtable_array_offset = be_u32(cblc, size_table)? as usize;
+        for i in 0..num_subtables {
+            let entry = subtable_array_offset + i as usize * 8;
+            let first_glyph = be_u16(cblc, entry)?;
+            let last_glyph = be_u16(cblc, entry + 2)?;
+            if (glyph as u16) < first_glyph || (glyph as u16) > last_glyph {
+                continue;
+            }
+            let index_subtable_offset = subtable_array_offset + be_u32(cblc, entry + 4)? as usize;
+            let image_format = be_u16(cblc, index_subtable_offset + 2)?;
+            // Real color-emoji CBDT tables store PNGs under formats 17-19 (format 1 is
+            // byte-aligned non-PNG coverage data, which isn't a color glyph at all). Each
+            // format prefixes the PNG bytes with a differently sized metrics/length header.
+            let header_len = match image_format {
+                17 => 5 + 4,  // smallGlyphMetrics + uint32 dataLen
+                18 => 8 + 4,  // bigGlyphMetrics + uint32 dataLen
+                19 => 4,      // uint32 dataLen only; metrics live in CBLC/EBLC
+                _ => continue,
+            };
+            let image_data_offset = be_u32(cblc, index_subtable_offset + 4)? as usize;
+            let rel_index = (glyph as u16 - first_glyph) as usize;
+            let loca_offset = index_subtable_offset + 8 + rel_index * 4;
+            let glyph_start = image_data_offset + be_u32(cblc, loca_offset)? as usize;
+            let glyph_end = image_data_offset + be_u32(cblc, loca_offset + 4)? as usize;
+            let record = cbdt.get(glyph_start..glyph_end)?;
+            let png_data = record.get(header_len..)?;
+            return image::load_from_memory(png_data).ok().map(|i| i.to_rgba8());
+        }
+        None
+    }
+
+    /// Rasterize and tint each layer of a `COLR` glyph with its paired `CPAL` palette color,
+    /// compositing them back-to-front onto `canvas`.
+    fn draw_colr_glyph(
+        canvas: &mut Canvas,
+        color_mask: &mut [bool],
+        font: &Font,
+        glyph: u32,
+        point_size: f32,
+        transform: Transform2F,
+    ) -> Result<(), TextError> {
+        let colr = font.load_font_table(tag(b"COLR")).ok_or(TextError::MissingColorTable)?;
+        let cpal = font.load_font_table(tag(b"CPAL")).ok_or(TextError::MissingColorTable)?;
+        let layers = colr_layers(&colr, glyph).unwrap_or_default();
+        if layers.is_empty() {
+            // Not a base glyph for any COLR record: draw it as plain monochrome, so it's left
+            // out of color_mask and gets tinted with the caller's foreground color.
+            font.rasterize_glyph(
+                canvas,
+                glyph,
+                point_size,
+                transform,
+                HintingOptions::None,
+                RasterizationOptions::GrayscaleAa,
+            )?;
+            return Ok(());
+        }
+        for (layer_glyph, palette_index) in layers {
+            let color = cpal_color(&cpal, palette_index).unwrap_or(Rgba([0, 0, 0, 255]));
+            let mut layer_canvas = Canvas::new(canvas.size, Format::Rgba32);
+            font.rasterize_glyph(
+                &mut layer_canvas,
+                layer_glyph,
+                point_size,
+                transform,
+                HintingOptions::None,
+                RasterizationOptions::GrayscaleAa,
+            )?;
+            tint_and_composite(canvas, color_mask, &layer_canvas, color);
+        }
+        Ok(())
+    }
+
+    fn colr_layers(colr: &[u8], glyph: u32) -> Option<Vec<(u32, u16)>> {
+        let num_base_glyphs = be_u16(colr, 2)?;
+        let base_glyph_offset = be_u32(colr, 4)? as usize;
+        let layer_offset = be_u32(colr, 8)? as usize;
+        for i in 0..num_base_glyphs {
+            let record = base_glyph_offset + i as usize * 6;
+            let base_glyph = be_u16(colr, record)?;
+            if base_glyph as u32 != glyph {
+                continue;
+            }
+            let first_layer = be_u16(colr, record + 2)?;
+            let num_layers = be_u16(colr, record + 4)?;
+            let mut layers = Vec::with_capacity(num_layers as usize);
+            for l in 0..num_layers {
+                let layer_record = layer_offset + (first_layer + l) as usize * 4;
+                let layer_glyph = be_u16(colr, layer_record)? as u32;
+                let palette_index = be_u16(colr, layer_record + 2)?;
+                layers.push((layer_glyph, palette_index));
+            }
+            return Some(layers);
+        }
+        None
+    }
+
+    fn cpal_color(cpal: &[u8], palette_index: u16) -> Option<Rgba<u8>> {
+        let num_color_records = be_u16(cpal, 6)?;
+        let color_records_offset = be_u32(cpal, 8)? as usize;
+        if palette_index >= num_color_records {
+            return None;
+        }
+        let record = color_records_offset + palette_index as usize * 4;
+        // CPAL stores color records as BGRA.
+        let b = *cpal.get(record)?;
+        let g = *cpal.get(record + 1)?;
+        let r = *cpal.get(record + 2)?;
+        let a = *cpal.get(record + 3)?;
+        Some(Rgba([r, g, b, a]))
+    }
+
+    /// Multiply `layer`'s coverage by `color` and composite it over `canvas` in place, marking
+    /// every touched pixel in `color_mask` as color (not monochrome coverage needing a tint).
+    fn tint_and_composite(canvas: &mut Canvas, color_mask: &mut [bool], layer: &Canvas, color: Rgba<u8>) {
+        let width = canvas.size.x() as usize;
+        for y in 0..canvas.size.y() {
+            let row = y as usize * canvas.stride;
+            let layer_row = y as usize * layer.stride;
+            for x in 0..canvas.size.x() as usize {
+                let coverage = layer.pixels[layer_row + x * 4 + 3];
+                if coverage == 0 {
+                    continue;
+                }
+                let a = (coverage as u32 * color.0[3] as u32 / 255) as u8;
+                for c in 0..3 {
+                    let src = canvas.pixels[row + x * 4 + c] as u32;
+                    let fg = color.0[c] as u32;
+                    canvas.pixels[row + x * 4 + c] =
+                        ((src * (255 - a as u32) + fg * a as u32) / 255) as u8;
+                }
+                canvas.pixels[row + x * 4 + 3] = canvas.pixels[row + x * 4 + 3].max(a);
+                color_mask[y as usize * width + x] = true;
+            }
+        }
+    }
+
+    /// Copy `image`'s straight-alpha RGBA pixels onto `canvas` at `(x0, y0)`, clipping to bounds,
+    /// and mark every written pixel in `color_mask` as color (not monochrome coverage).
+    fn composite_rgba_image(canvas: &mut Canvas, color_mask: &mut [bool], image: &RgbaImage, x0: i32, y0: i32) {
+        let width = canvas.size.x() as usize;
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let dst_x = x0 + x as i32;
+            let dst_y = y0 + y as i32;
+            if dst_x < 0 || dst_y < 0 || dst_x >= canvas.size.x() || dst_y >= canvas.size.y() {
+                continue;
+            }
+            if pixel.0[3] == 0 {
+                continue;
+            }
+            let row = dst_y as usize * canvas.stride;
+            let offset = row + dst_x as usize * 4;
+            canvas.pixels[offset..offset + 4].copy_from_slice(&pixel.0);
+            color_mask[dst_y as usize * width + dst_x as usize] = true;
+        }
+    }
+
+    fn be_u16(data: &[u8], offset: usize) -> Option<u16> {
+        data.get(offset..offset + 2)
+            .map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn be_u32(data: &[u8], offset: usize) -> Option<u32> {
+        data.get(offset..offset + 4)
+            .map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
     #[derive(Debug)]
     pub enum TextError {
         FontSelectionError(font_kit::error::SelectionError),
         FontLoadingError(font_kit::error::FontLoadingError),
         GlyphLoadingError(font_kit::error::GlyphLoadingError),
         MissingGlyphError(char),
+        MissingColorTable,
     }
 
-    fn text_dimensions(s: &str, font: &Font) -> Result<(i32, i32), TextError> {
+    fn text_dimensions(
+        s: &str,
+        fonts: &[Font],
+        point_size: f32,
+        fallback: &mut FallbackCache,
+    ) -> Result<(i32, i32), TextError> {
         let mut total_bounds = RectI::new(vec2i(0, 0), vec2i(0, 0));
         let mut cursor = 0;
-        for ch in s.chars() {
-            let glyph = font.glyph_for_char(ch).ok_or(TextError::MissingGlyphError(ch))?;
-            let bounds = font.typographic_bounds(glyph)?.to_i32();
+        for cluster in layout(s) {
+            let mut cluster_advance = 0;
+            for (i, ch) in cluster.chars().enumerate() {
+                let (font, glyph) = resolve_char(fonts, ch, fallback)?;
+                let bounds = font.typographic_bounds(glyph)?.scale(point_size).to_i32();
 
-            total_bounds.0[0] = total_bounds.0[0].min(cursor + bounds.min_x());
-            total_bounds.0[1] = total_bounds.0[1].min(bounds.min_y());
-            total_bounds.0[2] = total_bounds.0[2].max(cursor + bounds.max_x());
-            total_bounds.0[3] = total_bounds.0[3].max(bounds.max_y());
+                total_bounds.0[0] = total_bounds.0[0].min(cursor + bounds.min_x());
+                total_bounds.0[1] = total_bounds.0[1].min(bounds.min_y());
+                total_bounds.0[2] = total_bounds.0[2].max(cursor + bounds.max_x());
+                total_bounds.0[3] = total_bounds.0[3].max(bounds.max_y());
 
-            cursor += font.advance(glyph)?.0[0] as i32;
+                if i == 0 {
+                    cluster_advance = glyph_advance(&font, glyph, point_size)?;
+                }
+            }
+            cursor += cluster_advance;
         }
         Ok((total_bounds.width(), total_bounds.height()))
     }
@@ -130,6 +767,7 @@ mod text {
                 Self::FontLoadingError(err) => Some(err),
                 Self::GlyphLoadingError(err) => Some(err),
                 Self::MissingGlyphError(_) => None,
+                Self::MissingColorTable => None,
             }
         }
     }
@@ -141,6 +779,7 @@ mod text {
                 Self::FontLoadingError(err) => err.fmt(f),
                 Self::GlyphLoadingError(err) => err.fmt(f),
                 Self::MissingGlyphError(ch) => write!(f, "Did not find glyph for '{}'", ch),
+                Self::MissingColorTable => write!(f, "Expected color glyph table was not found"),
             }
         }
     }
@@ -162,6 +801,183 @@ mod text {
             Self::GlyphLoadingError(err)
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// The system's generic sans-serif font. Unlike [`pick_random_font`], which can land on
+        /// a symbol or icon font with no Latin coverage, `FamilyName::SansSerif` is a generic
+        /// name font-kit always resolves to some installed sans-serif, making tests that need a
+        /// font covering plain ASCII text deterministic.
+        fn test_font() -> Font {
+            let handle = SystemSource::new()
+                .select_best_match(&[FamilyName::SansSerif], &Properties::new())
+                .expect("a system sans-serif font must be installed to run this test");
+            Font::from_handle(&handle).expect("failed to load the resolved sans-serif font")
+        }
+
+        #[test]
+        fn layout_reorders_rtl_runs_for_visual_order() {
+            // "abc " (LTR) followed by three Hebrew letters (RTL).
+            let s = "abc \u{5d0}\u{5d1}\u{5d2}";
+            let clusters = layout(s);
+            assert_eq!(&clusters[..4], &["a", "b", "c", " "]);
+            assert_eq!(&clusters[4..], &["\u{5d2}", "\u{5d1}", "\u{5d0}"]);
+        }
+
+        #[test]
+        fn glyph_advance_is_scaled_like_bounds() {
+            let font = test_font();
+            let glyph = font.glyph_for_char('A').expect("font should have a glyph for 'A'");
+            let point_size = 24.0;
+            let bounds = font
+                .typographic_bounds(glyph)
+                .unwrap()
+                .scale(point_size)
+                .to_i32();
+            let advance = glyph_advance(&font, glyph, point_size).unwrap();
+            // An advance stuck near zero (missing the same point_size scaling as bounds) would
+            // stack every glyph on top of the last instead of laying them out left-to-right.
+            assert!(
+                advance as f32 > bounds.width() as f32 * 0.3,
+                "advance {} looks unscaled next to bounds width {}",
+                advance,
+                bounds.width()
+            );
+        }
+
+        #[test]
+        fn repeated_draw_reuses_cached_glyphs() {
+            let font = test_font();
+            let mut fallback = FallbackCache::new();
+            let mut cache = GlyphCache::new();
+            draw_with_font(
+                "hello",
+                &[font.clone()],
+                24.0,
+                ColorGlyphMode::Never,
+                &mut fallback,
+                &mut cache,
+            )
+            .expect("first draw should succeed");
+            let misses_after_first_draw = cache.misses();
+
+            draw_with_font(
+                "hello",
+                &[font],
+                24.0,
+                ColorGlyphMode::Never,
+                &mut fallback,
+                &mut cache,
+            )
+            .expect("second draw should succeed");
+
+            assert_eq!(
+                cache.misses(),
+                misses_after_first_draw,
+                "second draw of the same string must not rasterize any new glyphs"
+            );
+        }
+
+        #[test]
+        fn layout_keeps_combining_marks_in_one_cluster() {
+            // "e" followed by COMBINING ACUTE ACCENT is a single grapheme cluster.
+            let s = "e\u{0301}bc";
+            let clusters = layout(s);
+            assert_eq!(clusters, vec!["e\u{0301}", "b", "c"]);
+        }
+    }
+}
+
+mod compositor {
+    //! Gamma-correct alpha compositing of rasterized text onto background images.
+    use image::{Rgb, RgbImage};
+
+    use super::text::RasterizedText;
+
+    /// Precomputed gamma curve used to (a) map linear 0..=255 glyph coverage to the alpha
+    /// actually used for blending, so anti-aliased edges look right perceptually instead of
+    /// washed out, and (b) linearize/re-encode the sRGB-ish color channels being blended, so the
+    /// blend itself happens in linear light rather than on raw sRGB bytes.
+    pub struct GammaLut {
+        alpha: [u8; 256],
+        to_linear: [f32; 256],
+        gamma: f32,
+    }
+
+    impl GammaLut {
+        pub fn new(gamma: f32) -> Self {
+            let mut alpha = [0u8; 256];
+            let mut to_linear = [0.0f32; 256];
+            for coverage in 0..256 {
+                let linear = coverage as f32 / 255.0;
+                alpha[coverage] = (linear.powf(1.0 / gamma) * 255.0).round() as u8;
+                to_linear[coverage] = linear.powf(gamma);
+            }
+            Self { alpha, to_linear, gamma }
+        }
+
+        fn apply(&self, coverage: u8) -> u8 {
+            self.alpha[coverage as usize]
+        }
+
+        /// Decode an sRGB-ish 0..=255 channel value into linear light.
+        fn to_linear(&self, component: u8) -> f32 {
+            self.to_linear[component as usize]
+        }
+
+        /// Encode a linear-light value back into an sRGB-ish 0..=255 channel value.
+        fn to_srgb(&self, linear: f32) -> u8 {
+            (linear.clamp(0.0, 1.0).powf(1.0 / self.gamma) * 255.0).round() as u8
+        }
+    }
+
+    impl Default for GammaLut {
+        /// A gamma of 2.0 sits in the middle of the ~1.8-2.2 range typical displays expect.
+        fn default() -> Self {
+            Self::new(2.0)
+        }
+    }
+
+    /// Blend `text`'s canvas over `image` with its top-left corner at `(x0, y0)`.
+    ///
+    /// Pixels `text` marks as color glyph pixels (see [`RasterizedText::is_color_pixel`]) are
+    /// blended using their own color so emoji and bitmap glyphs are not recolored; all other
+    /// pixels are treated as monochrome coverage and tinted with `fg`. Both the background
+    /// and source color are decoded to linear light before blending and re-encoded to sRGB
+    /// afterwards, so anti-aliased edges look right instead of washed out.
+    pub fn composite(image: &mut RgbImage, text: &RasterizedText, x0: i32, y0: i32, fg: Rgb<u8>, lut: &GammaLut) {
+        let canvas = &text.canvas;
+        for y in 0..canvas.size.y() {
+            let dst_y = y0 + y;
+            if dst_y < 0 || dst_y as u32 >= image.height() {
+                continue;
+            }
+            let row = y as usize * canvas.stride;
+            for x in 0..canvas.size.x() {
+                let dst_x = x0 + x;
+                if dst_x < 0 || dst_x as u32 >= image.width() {
+                    continue;
+                }
+                let offset = row + x as usize * 4;
+                let pixel = &canvas.pixels[offset..offset + 4];
+                let (r, g, b, coverage) = (pixel[0], pixel[1], pixel[2], pixel[3]);
+                if coverage == 0 {
+                    continue;
+                }
+                let src = if text.is_color_pixel(x, y) { Rgb([r, g, b]) } else { fg };
+                let alpha = lut.apply(coverage) as f32 / 255.0;
+                let dst = image.get_pixel_mut(dst_x as u32, dst_y as u32);
+                for c in 0..3 {
+                    let bg_linear = lut.to_linear(dst.0[c]);
+                    let fg_linear = lut.to_linear(src.0[c]);
+                    let out_linear = bg_linear * (1.0 - alpha) + fg_linear * alpha;
+                    dst.0[c] = lut.to_srgb(out_linear);
+                }
+            }
+        }
+    }
 }
 
 /// Seed a new background image that can be updated afterwards.
@@ -173,42 +989,100 @@ pub struct SeedArgs {
     height: u16,
     #[clap(short, long, default_value = "#FFFFFF")]
     background: Color,
+    /// How to handle color glyph tables (emoji, bitmap fonts) when drawing text.
+    #[clap(long, value_enum, default_value_t = ColorGlyphMode::Auto)]
+    color_glyphs: ColorGlyphMode,
+    /// Gamma used to correct glyph coverage before blending it onto the background.
+    #[clap(long, default_value_t = 2.0)]
+    text_gamma: f32,
 }
 
 /// Update an existing background image.
 #[derive(Debug, Parser)]
 pub struct UpdateArgs {}
 
+/// Everything that can go wrong while running an `img` subcommand.
+#[derive(Debug)]
+pub enum ImgError {
+    Text(text::TextError),
+    Image(image::ImageError),
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for ImgError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Text(err) => err.fmt(f),
+            Self::Image(err) => err.fmt(f),
+            Self::Io(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ImgError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Text(err) => Some(err),
+            Self::Image(err) => Some(err),
+            Self::Io(err) => Some(err),
+        }
+    }
+}
+
+impl From<text::TextError> for ImgError {
+    fn from(err: text::TextError) -> Self {
+        Self::Text(err)
+    }
+}
+
+impl From<image::ImageError> for ImgError {
+    fn from(err: image::ImageError) -> Self {
+        Self::Image(err)
+    }
+}
+
+impl From<std::io::Error> for ImgError {
+    fn from(err: std::io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
 /// Process the img subcommand.
-pub fn command(arg: Command) {
+pub fn command(arg: Command) -> Result<(), ImgError> {
     match arg {
         Command::Seed(args) => seed(args),
         Command::Update(args) => update(args),
     }
 }
 
-fn seed(args: SeedArgs) {
+fn seed(args: SeedArgs) -> Result<(), ImgError> {
     let mut generated_image = image::RgbImage::from_pixel(
         args.width as u32,
         args.height as u32,
         args.background.into(),
     );
 
-    imageproc::drawing::draw_text_mut(
+    // Keep the seeded text comfortably inside the background, leaving a margin on each side.
+    let max_text_size = ((args.width as u32) * 3 / 4, (args.height as u32) * 3 / 4);
+    let text = text::draw("tecli", args.height as u32 / 20, max_text_size, args.color_glyphs)?;
+
+    let x0 = (args.width as i32 - text.canvas.size.x()) / 2;
+    let y0 = (args.height as i32 - text.canvas.size.y()) / 2;
+    let gamma = compositor::GammaLut::new(args.text_gamma);
+    compositor::composite(
         &mut generated_image,
+        &text,
+        x0,
+        y0,
         args.background.inverse().into(),
-        (args.width / 2) as i32,
-        (args.height / 2) as i32,
-        Scale{1.0, 1.0},
-        font,
-        text,
+        &gamma,
     );
 
-    generated_image
-        .save("test.png")
-        .expect("Failed to save seeded image");
+    generated_image.save("test.png")?;
+    Ok(())
 }
 
-fn update(_: UpdateArgs) {
-    println!("updating image")
+fn update(_: UpdateArgs) -> Result<(), ImgError> {
+    println!("updating image");
+    Ok(())
 }
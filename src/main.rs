@@ -24,7 +24,17 @@ fn main() {
     // Parse empty args, as the user may have supplied a help flag
     let args = Cli::parse();
 
-    match args.command {
+    let result = match args.command {
         Commands::Img(img) => img::command(img),
+    };
+
+    if let Err(err) = result {
+        eprintln!("Error: {}", err);
+        let mut source = std::error::Error::source(&err);
+        while let Some(err) = source {
+            eprintln!("Caused by: {}", err);
+            source = err.source();
+        }
+        std::process::exit(1);
     }
 }